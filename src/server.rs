@@ -4,19 +4,35 @@
 
 use std::io::{self, BufReader};
 use std::env;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::fs::File;
 use std::collections::HashMap;
+use std::sync::Arc;
 
 use futures;
 use futures::future::Future;
-use hyper::{mime, Error};
+use futures::Stream;
+use hyper::{mime, Error, StatusCode};
 use hyper::header::{
+    Accept,
+    AcceptEncoding,
     AccessControlAllowHeaders,
     AccessControlAllowOrigin,
+    ByteRangeSpec,
+    ContentEncoding,
     ContentLength,
+    ContentRange,
+    ContentRangeSpec,
     ContentType,
+    ETag,
+    Encoding,
+    EntityTag,
     Headers,
+    HttpDate,
+    IfModifiedSince,
+    IfNoneMatch,
+    LastModified,
+    Range,
     Server,
 };
 use hyper::server::{Http, Request, Response, Service};
@@ -24,15 +40,130 @@ use unicase::Ascii;
 use percent_encoding::percent_decode;
 use tera::{Tera, Context};
 use mime_guess::get_mime_type_opt;
+use serde_derive::Serialize;
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Core;
+use tokio_rustls::ServerConfigExt;
+use rustls::internal::pemfile::{certs, rsa_private_keys};
+use rustls::ServerConfig;
+use regex::Regex;
+use flate2::Compression;
+use flate2::write::GzEncoder;
 
 const SERVER_VERSION: &str =
     concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
 
-#[derive(Debug, Copy, Clone)]
+/// Size of each chunk streamed from disk for a file response.
+const CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Largest file eagerly read into memory and compressed whole. Past this,
+/// a non-ranged request is served streaming and uncompressed instead, so a
+/// client that simply advertises `Accept-Encoding: gzip` (i.e. almost all
+/// of them) can't force the whole file into memory regardless of size.
+const MAX_EAGER_COMPRESS_SIZE: u64 = 2 * 1024 * 1024;
+
+/// An inclusive byte range resolved against a concrete file size.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct ResolvedRange {
+    start: u64,
+    end: u64,
+    total: u64,
+}
+
+impl ResolvedRange {
+    fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+}
+
+/// Parse a `Range` header against a file of `total` bytes.
+///
+/// Returns `Ok(None)` when no range was requested, `Ok(Some(range))` for a
+/// satisfiable single range, and `Err(())` when the range is syntactically
+/// valid but falls outside `0..total`. Only the first range is honoured;
+/// multi-range requests are treated as a request for the first range.
+fn parse_range(headers: &Headers, total: u64) -> Result<Option<ResolvedRange>, ()> {
+    let range = match headers.get::<Range>() {
+        Some(&Range::Bytes(ref specs)) => specs,
+        _ => return Ok(None),
+    };
+    let spec = match range.first() {
+        Some(spec) => spec,
+        None => return Ok(None),
+    };
+    let (start, end) = match *spec {
+        ByteRangeSpec::FromTo(start, end) => (start, end),
+        ByteRangeSpec::AllFrom(start) => (start, total.saturating_sub(1)),
+        ByteRangeSpec::Last(len) => {
+            if len == 0 {
+                return Err(());
+            }
+            (total.saturating_sub(len.min(total)), total.saturating_sub(1))
+        }
+    };
+    if total == 0 || start > end || end >= total {
+        return Err(());
+    }
+    Ok(Some(ResolvedRange { start, end, total }))
+}
+
+/// A stream of fixed-size `Chunk`s read from `file`, starting at `start` and
+/// yielding at most `remaining` bytes in total.
+struct FileChunkStream {
+    file: File,
+    remaining: u64,
+}
+
+impl FileChunkStream {
+    fn new(mut file: File, start: u64, len: u64) -> io::Result<Self> {
+        use std::io::Seek;
+        file.seek(io::SeekFrom::Start(start))?;
+        Ok(FileChunkStream { file, remaining: len })
+    }
+}
+
+impl Stream for FileChunkStream {
+    type Item = hyper::Chunk;
+    type Error = Error;
+
+    fn poll(&mut self) -> futures::Poll<Option<Self::Item>, Self::Error> {
+        use std::io::Read;
+        if self.remaining == 0 {
+            return Ok(futures::Async::Ready(None));
+        }
+        let want = CHUNK_SIZE.min(self.remaining) as usize;
+        let mut buf = vec![0u8; want];
+        let read = self.file.read(&mut buf)?;
+        if read == 0 {
+            return Ok(futures::Async::Ready(None));
+        }
+        buf.truncate(read);
+        self.remaining -= read as u64;
+        Ok(futures::Async::Ready(Some(hyper::Chunk::from(buf))))
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct ServerOptions {
     host: &'static str,
     port: u16,
     cors: bool,
+    /// Path to a PEM-encoded certificate chain. Serving over HTTPS requires
+    /// both this and `tls_key` to be set.
+    tls_cert: Option<PathBuf>,
+    /// Path to a PEM-encoded RSA private key, paired with `tls_cert`.
+    tls_key: Option<PathBuf>,
+    /// Only list files whose name matches this pattern; directories are
+    /// always listed.
+    filter: Option<Regex>,
+    /// Hide files larger than this many bytes from directory listings.
+    max_size: Option<u64>,
+    /// The directory served as the root of the URL namespace. All requests
+    /// are sandboxed to this directory; see `resolve_path`.
+    root: PathBuf,
+    /// Render `.md`/`.markdown` files to HTML instead of serving them as
+    /// plain text. Always bypassed by `?raw=1`.
+    render_markdown: bool,
 }
 
 impl Default for ServerOptions {
@@ -41,30 +172,144 @@ impl Default for ServerOptions {
             host: "127.0.0.1",
             port: 8888,
             cors: false,
+            tls_cert: None,
+            tls_key: None,
+            filter: None,
+            max_size: None,
+            root: env::current_dir().unwrap_or_else(|_| PathBuf::from(".")),
+            render_markdown: false,
         }
     }
 }
 
+/// Why `resolve_path` refused a request, so callers can tell an ordinary
+/// missing file apart from an actual sandbox violation.
+#[derive(Debug, PartialEq, Eq)]
+enum PathResolutionError {
+    /// The path doesn't exist under `root` (404).
+    NotFound,
+    /// The path resolves outside `root`, or is otherwise not servable (403).
+    Forbidden,
+}
+
+/// Resolve a percent-decoded request path against `root`, rejecting
+/// anything that would escape it.
+///
+/// `root` is assumed to already be canonical (see `MyServer::new`); `..`,
+/// `.`, and empty segments are dropped or rejected outright rather than
+/// normalized positionally, so a request can't walk back out of `root` no
+/// matter how the segments are arranged. The joined path is then
+/// canonicalized and checked to still live under `root`, which also
+/// resolves any symlink that might otherwise escape it.
+fn resolve_path(root: &Path, decoded: &str) -> Result<PathBuf, PathResolutionError> {
+    let mut path = root.to_path_buf();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => continue,
+            ".." => return Err(PathResolutionError::Forbidden),
+            seg => path.push(seg),
+        }
+    }
+
+    let canonical_path = match path.canonicalize() {
+        Ok(p) => p,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(PathResolutionError::NotFound);
+        }
+        Err(_) => return Err(PathResolutionError::Forbidden),
+    };
+    if canonical_path.starts_with(root) {
+        Ok(canonical_path)
+    } else {
+        Err(PathResolutionError::Forbidden)
+    }
+}
+
+/// Load a certificate chain and private key from PEM files into a rustls
+/// server configuration.
+fn load_tls_config(cert_path: &Path, key_path: &Path) -> io::Result<ServerConfig> {
+    let cert_chain = certs(&mut BufReader::new(File::open(cert_path)?))
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid certificate"))?;
+    let mut keys = rsa_private_keys(&mut BufReader::new(File::open(key_path)?))
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid private key"))?;
+    let key = keys.pop()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no private key found"))?;
+
+    let mut config = ServerConfig::new(rustls::NoClientAuth::new());
+    config.set_single_cert(cert_chain, key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(config)
+}
+
 #[derive(Debug)]
 pub struct MyServer {
     options: ServerOptions,
 }
 
 impl MyServer {
-    pub fn new(options: ServerOptions) -> Self {
+    /// Construct a server, canonicalizing `options.root` once so every
+    /// later comparison against it (in `resolve_path`, `handle_dir`, ...)
+    /// is comparing two canonical paths instead of risking a relative or
+    /// symlinked root that never matches.
+    pub fn new(mut options: ServerOptions) -> Self {
+        options.root = options.root.canonicalize().unwrap_or_else(|e| {
+            panic!("failed to canonicalize root {:?}: {}", options.root, e)
+        });
         Self { options }
     }
 
-    /// Run the server.
+    /// Run the server, serving over HTTPS when both `tls_cert` and
+    /// `tls_key` are configured, and falling back to plain HTTP otherwise.
+    ///
+    /// Configuring exactly one of the two is treated as a misconfiguration
+    /// and panics rather than silently serving the content in the clear.
     pub fn serve(&self) {
         println!("{:?}", self.options);
         let options = self.options.clone();
-        let ServerOptions { host, port, .. } = options;
+        let ServerOptions { host, port, .. } = options.clone();
         let addr = format!("{}:{}", host, port).parse().unwrap();
-        let server = Http::new().bind(&addr, move || {
-            Ok(MyService::new(options))
-        }).unwrap();
-        server.run().unwrap();
+
+        match (&options.tls_cert, &options.tls_key) {
+            (Some(_), None) | (None, Some(_)) => {
+                panic!(
+                    "both --tls-cert and --tls-key must be set to serve over HTTPS; \
+                     refusing to silently fall back to plain HTTP"
+                );
+            }
+            (Some(cert_path), Some(key_path)) => {
+                let tls_config = Arc::new(
+                    load_tls_config(cert_path, key_path)
+                        .expect("failed to load TLS certificate/key"),
+                );
+
+                let mut core = Core::new().unwrap();
+                let handle = core.handle();
+                let listener = TcpListener::bind(&addr, &handle).unwrap();
+                let http = Http::new();
+
+                let server = listener.incoming().for_each(move |(sock, _addr)| {
+                    let options = options.clone();
+                    let http = http.clone();
+                    let handle = handle.clone();
+                    let accept = tls_config.accept_async(sock).then(move |tls| {
+                        if let Ok(tls) = tls {
+                            let conn = http.serve_connection(tls, MyService::new(options));
+                            handle.spawn(conn.map_err(|_| ()));
+                        }
+                        Ok(())
+                    });
+                    handle.spawn(accept);
+                    Ok(())
+                });
+                core.run(server).unwrap();
+            }
+            _ => {
+                let server = Http::new().bind(&addr, move || {
+                    Ok(MyService::new(options.clone()))
+                }).unwrap();
+                server.run().unwrap();
+            }
+        }
     }
 }
 
@@ -94,36 +339,12 @@ impl MyService {
         // Remove leading slash.
         let req_path = &req.path()[1..].as_bytes();
         // URI percent decode.
-        let req_path = percent_decode(req_path)
+        let decoded_path = percent_decode(req_path)
             .decode_utf8()
             .unwrap()
             .into_owned();
-        let req_path = env::current_dir().unwrap().join(req_path);
-
-        let error_handler = |e: io::Error| Vec::from(format!("Error: {}", e));
-        let body = if req_path.is_dir() {
-            handle_dir(&req_path).unwrap_or_else(error_handler)
-        } else {
-            handle_file(&req_path).unwrap_or_else(error_handler)
-        };
-
-        // MIME type guessing.
-        let mime_type = if req_path.is_dir() {
-            mime::TEXT_HTML_UTF_8
-        } else {
-            match req_path.extension() {
-                Some(ext) => {
-                    get_mime_type_opt(ext.to_str().unwrap_or(""))
-                        .unwrap_or(mime::TEXT_PLAIN)
-                }
-                None => mime::TEXT_PLAIN,
-            }
-        };
 
         let mut headers = Headers::new();
-        // Default headers
-        headers.set(ContentType(mime_type));
-        headers.set(ContentLength(body.len() as u64));
         headers.set(Server::new(SERVER_VERSION));
         // CORS headers
         if self.options.cors {
@@ -136,77 +357,868 @@ impl MyService {
             ]));
         }
 
-        Response::new()
-            .with_headers(headers)
-            .with_body(body)
+        let req_path = match resolve_path(&self.options.root, &decoded_path) {
+            Ok(path) => path,
+            Err(PathResolutionError::NotFound) => {
+                return Response::new()
+                    .with_status(StatusCode::NotFound)
+                    .with_headers(headers);
+            }
+            Err(PathResolutionError::Forbidden) => {
+                return Response::new()
+                    .with_status(StatusCode::Forbidden)
+                    .with_headers(headers);
+            }
+        };
+
+        if req_path.is_dir() {
+            let as_json = wants_json(req);
+            let body = handle_dir(&req_path, as_json, &self.options)
+                .unwrap_or_else(|e| Vec::from(format!("Error: {}", e)));
+            let mime_type = if as_json { mime::APPLICATION_JSON } else { mime::TEXT_HTML_UTF_8 };
+            headers.set(ContentType(mime_type.clone()));
+            let body = finalize_body(req, &mime_type, &mut headers, body);
+            return Response::new().with_headers(headers).with_body(body);
+        }
+
+        // MIME type guessing.
+        let mime_type = match req_path.extension() {
+            Some(ext) => {
+                get_mime_type_opt(ext.to_str().unwrap_or(""))
+                    .unwrap_or(mime::TEXT_PLAIN)
+            }
+            None => mime::TEXT_PLAIN,
+        };
+
+        if self.options.render_markdown
+            && is_markdown(&req_path)
+            && !wants_raw(req)
+            && accepts_html(req)
+        {
+            let body = render_markdown_page(&req_path)
+                .unwrap_or_else(|e| Vec::from(format!("Error: {}", e)));
+            headers.set(ContentType(mime::TEXT_HTML_UTF_8));
+            let body = finalize_body(req, &mime::TEXT_HTML_UTF_8, &mut headers, body);
+            return Response::new().with_headers(headers).with_body(body);
+        }
+        headers.set(ContentType(mime_type.clone()));
+
+        // Conditional GET: attach validators and short-circuit with
+        // `304 Not Modified` when the client's cached copy is still fresh.
+        if let Ok(metadata) = req_path.metadata() {
+            if let Some((etag, last_modified)) = file_validators(&metadata) {
+                let cached = is_cached(req.headers(), &etag, last_modified);
+
+                headers.set(ETag(etag));
+                headers.set(LastModified(last_modified));
+                if cached {
+                    return Response::new()
+                        .with_status(StatusCode::NotModified)
+                        .with_headers(headers);
+                }
+            }
+        }
+
+        // Compression buffers the whole body in memory, so it only applies
+        // to full (non-ranged), text-like files up to MAX_EAGER_COMPRESS_SIZE.
+        // Larger files keep streaming uncompressed rather than undoing
+        // chunk0-1's point of never buffering a whole file in memory;
+        // ranged and binary responses are left untouched outright.
+        if req.headers().get::<Range>().is_none() && is_compressible(&mime_type) {
+            if let Some(encoding) = negotiate_encoding(req) {
+                let small_enough = req_path.metadata()
+                    .map(|m| m.len() <= MAX_EAGER_COMPRESS_SIZE)
+                    .unwrap_or(false);
+                if small_enough {
+                    let compressed = std::fs::read(&req_path)
+                        .and_then(|raw| compress_body(&raw, &encoding));
+                    if let Ok(compressed) = compressed {
+                        headers.set(ContentEncoding(vec![encoding]));
+                        headers.set(ContentLength(compressed.len() as u64));
+                        return Response::new().with_headers(headers).with_body(compressed);
+                    }
+                }
+            }
+        }
+
+        match handle_file(&req_path, req.headers()) {
+            Ok(FileResponse::Full { body, len }) => {
+                headers.set(ContentLength(len));
+                Response::new().with_headers(headers).with_body(body)
+            }
+            Ok(FileResponse::Partial { body, range }) => {
+                headers.set(ContentLength(range.len()));
+                headers.set(ContentRange(ContentRangeSpec::Bytes {
+                    range: Some((range.start, range.end)),
+                    instance_length: Some(range.total),
+                }));
+                Response::new()
+                    .with_status(StatusCode::PartialContent)
+                    .with_headers(headers)
+                    .with_body(body)
+            }
+            Err(FileError::RangeNotSatisfiable { total }) => {
+                headers.set(ContentRange(ContentRangeSpec::Bytes {
+                    range: None,
+                    instance_length: Some(total),
+                }));
+                Response::new()
+                    .with_status(StatusCode::RangeNotSatisfiable)
+                    .with_headers(headers)
+            }
+            Err(FileError::Io(e)) => {
+                let body = Vec::from(format!("Error: {}", e));
+                headers.set(ContentType(mime::TEXT_PLAIN_UTF_8));
+                let body = finalize_body(req, &mime::TEXT_PLAIN_UTF_8, &mut headers, body);
+                Response::new().with_headers(headers).with_body(body)
+            }
+        }
     }
 }
 
-/// Send a HTML page of all files under the path.
-fn handle_dir(dir_path: &Path) -> io::Result<Vec<u8>> {
-    let mut files = Vec::new();
-    let base_path = &env::current_dir()?;
+/// File extension to category label, consulted while building each entry so
+/// the index can show an icon/class per file type. Falls back to `"file"`.
+const CATEGORY_TABLE: &[(&str, &str)] = &[
+    ("zip", "archive"), ("tar", "archive"), ("gz", "archive"), ("xz", "archive"),
+    ("bz2", "archive"), ("7z", "archive"), ("rar", "archive"),
+    ("png", "image"), ("jpg", "image"), ("jpeg", "image"), ("gif", "image"),
+    ("svg", "image"), ("webp", "image"), ("bmp", "image"), ("ico", "image"),
+    ("rs", "code"), ("py", "code"), ("js", "code"), ("ts", "code"), ("go", "code"),
+    ("c", "code"), ("cpp", "code"), ("h", "code"), ("java", "code"), ("rb", "code"),
+    ("sh", "code"), ("html", "code"), ("css", "code"), ("json", "code"), ("toml", "code"),
+    ("yml", "code"), ("yaml", "code"),
+    ("md", "document"), ("markdown", "document"), ("txt", "document"), ("pdf", "document"),
+    ("doc", "document"), ("docx", "document"),
+];
 
-    // Prepare dirname of current dir relative to base path.
-    let dir_name = {
-        let base_parent = base_path.parent().unwrap_or(base_path);
-        let path = dir_path.strip_prefix(base_parent).unwrap();
-        format!("{}/", path.to_str().unwrap())
-    };
+/// Categorize a file by its extension, e.g. `"archive"`, `"image"`,
+/// `"code"`, `"document"`. Directories and unrecognized extensions map to
+/// `"directory"` and `"file"` respectively.
+fn category_for(name: &str, is_dir: bool) -> &'static str {
+    if is_dir {
+        return "directory";
+    }
+    let ext = Path::new(name).extension().and_then(|e| e.to_str()).unwrap_or("");
+    CATEGORY_TABLE.iter()
+        .find(|(e, _)| e.eq_ignore_ascii_case(ext))
+        .map(|(_, category)| *category)
+        .unwrap_or("file")
+}
+
+/// Render a byte count in human units (B/kB/MB/GB/TB), dividing by 1024
+/// repeatedly while the value exceeds it.
+fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "kB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size > 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// A single entry in a directory listing, shared between the HTML and JSON
+/// renderings of `handle_dir`.
+#[derive(Debug, Serialize)]
+struct DirEntry {
+    name: String,
+    path: String,
+    is_dir: bool,
+    size: u64,
+    size_human: String,
+    category: &'static str,
+    /// Seconds since the Unix epoch, or `0` if the mtime couldn't be read.
+    modified: u64,
+}
+
+/// Collect the entries of `dir_path`, relative to `base_path`, including a
+/// leading `..` entry unless `dir_path` is the served root.
+///
+/// Files whose name fails `options.filter` (when set) are skipped, as are
+/// files larger than `options.max_size` (when set); directories are exempt
+/// from both so a filter never hides the ability to navigate into them.
+/// The remaining entries are sorted with directories first, then by name.
+fn collect_dir_entries(
+    dir_path: &Path,
+    base_path: &Path,
+    options: &ServerOptions,
+) -> io::Result<Vec<DirEntry>> {
+    let mut entries = Vec::new();
+    let mut parent = None;
 
-    // Item for popping back to parent directory.
     if base_path != dir_path {
         let parent_path = format!("/{}", dir_path
             .parent().unwrap()
             .strip_prefix(base_path).unwrap()
             .to_str().unwrap()
-        ).to_owned();
-        let mut map = HashMap::with_capacity(2);
-        map.insert("name", "..".to_owned());
-        map.insert("path", parent_path);
-        files.push(map);
+        );
+        parent = Some(DirEntry {
+            name: "..".to_owned(),
+            path: parent_path,
+            is_dir: true,
+            size: 0,
+            size_human: format_size(0),
+            category: "directory",
+            modified: 0,
+        });
     }
 
     for entry in dir_path.read_dir()? {
-        entry?.path()
-            .strip_prefix(base_path) // Strip prefix to build a relative path.
-            .and_then(|rel_path| {
-                // Construct file name.
-                let name = {
-                    let mut name = rel_path
-                        .file_name().unwrap()
-                        .to_str().unwrap()
-                        .to_owned();
-                    if rel_path.is_dir() {
-                        name.push('/');
-                    }
-                    name
-                };
-                // Construct hyperlink.
-                let path = format!("/{}", rel_path.to_str().unwrap());
-                // Use HashMap for default serialization Tera provides.
-                let mut map = HashMap::with_capacity(2);
-                map.insert("name", name);
-                map.insert("path", path);
-                files.push(map);
-                Ok(())
-            }).unwrap_or(()); // Prevent returning Result.
+        let entry = entry?;
+        let rel_path = match entry.path().strip_prefix(base_path) {
+            Ok(p) => p.to_owned(),
+            Err(_) => continue,
+        };
+        let metadata = entry.metadata()?;
+        let is_dir = metadata.is_dir();
+        let size = if is_dir { 0 } else { metadata.len() };
+
+        let name = {
+            let mut name = rel_path.file_name().unwrap().to_str().unwrap().to_owned();
+            if is_dir {
+                name.push('/');
+            }
+            name
+        };
+
+        if let Some(ref filter) = options.filter {
+            if !is_dir && !filter.is_match(&name) {
+                continue;
+            }
+        }
+        if let Some(max_size) = options.max_size {
+            if !is_dir && size > max_size {
+                continue;
+            }
+        }
+
+        let path = format!("/{}", rel_path.to_str().unwrap());
+        let modified = metadata.modified()
+            .ok()
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        entries.push(DirEntry {
+            category: category_for(&name, is_dir),
+            name,
+            path,
+            is_dir,
+            size,
+            size_human: format_size(size),
+            modified,
+        });
+    }
+
+    // Directories first, then stable alphabetical order within each group.
+    entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+
+    if let Some(parent) = parent {
+        entries.insert(0, parent);
+    }
+    Ok(entries)
+}
+
+/// Whether the request's raw query string contains the bare pair `key=value`.
+fn query_has(req: &Request, pair: &str) -> bool {
+    req.query().map_or(false, |q| q.split('&').any(|p| p == pair))
+}
+
+/// Whether the request prefers a JSON directory listing over the default
+/// HTML page, either via `?format=json` or an `Accept` header that ranks
+/// `application/json` at least as highly as `text/html`.
+fn wants_json(req: &Request) -> bool {
+    if query_has(req, "format=json") {
+        return true;
     }
+    match req.headers().get::<Accept>() {
+        Some(accept) => {
+            let json_q = accept.iter()
+                .find(|item| item.item == mime::APPLICATION_JSON)
+                .map(|item| item.quality);
+            let html_q = accept.iter()
+                .find(|item| item.item == mime::TEXT_HTML)
+                .map(|item| item.quality);
+            match (json_q, html_q) {
+                (Some(j), html_q) if j > hyper::header::q(0) => html_q.map_or(true, |h| j >= h),
+                _ => false,
+            }
+        }
+        None => false,
+    }
+}
+
+/// Whether `?raw=1` was given, which always bypasses Markdown rendering so
+/// the source file stays downloadable.
+fn wants_raw(req: &Request) -> bool {
+    query_has(req, "raw=1")
+}
+
+/// Whether the client's `Accept` header allows an HTML response, which is
+/// true both when it's absent (most non-browser clients) and when it lists
+/// `text/html` or a wildcard with nonzero quality.
+fn accepts_html(req: &Request) -> bool {
+    match req.headers().get::<Accept>() {
+        Some(accept) => accept.iter().any(|item| {
+            item.quality > hyper::header::q(0) && (
+                item.item == mime::TEXT_HTML
+                    || item.item == mime::STAR_STAR
+            )
+        }),
+        None => true,
+    }
+}
+
+/// Whether `mime_type` is worth compressing: text-like content such as
+/// HTML listings, plain text, JSON, CSS, JS and Markdown. Already-compressed
+/// binary types (images, archives) are left untouched.
+fn is_compressible(mime_type: &mime::Mime) -> bool {
+    mime_type.type_() == mime::TEXT
+        || *mime_type == mime::APPLICATION_JSON
+        || *mime_type == mime::APPLICATION_JAVASCRIPT
+        || *mime_type == mime::APPLICATION_JAVASCRIPT_UTF_8
+}
+
+/// Pick the most preferred codec this server supports from the request's
+/// `Accept-Encoding` header, favouring higher `q` values and, on a tie,
+/// brotli over gzip.
+fn negotiate_encoding(req: &Request) -> Option<Encoding> {
+    let accepted = req.headers().get::<AcceptEncoding>()?;
+    let mut candidates: Vec<_> = accepted.iter()
+        .filter(|qi| qi.quality > hyper::header::q(0) && supported_encoding(&qi.item))
+        .collect();
+    let is_br = |e: &Encoding| *e == Encoding::EncodingExt("br".to_owned());
+    candidates.sort_by(|a, b| {
+        b.quality.cmp(&a.quality).then_with(|| is_br(&b.item).cmp(&is_br(&a.item)))
+    });
+    candidates.into_iter().next().map(|qi| qi.item.clone())
+}
+
+fn supported_encoding(encoding: &Encoding) -> bool {
+    match *encoding {
+        Encoding::Gzip => true,
+        Encoding::EncodingExt(ref name) => name == "br",
+        _ => false,
+    }
+}
+
+/// Compress `body` with the negotiated `encoding`.
+fn compress_body(body: &[u8], encoding: &Encoding) -> io::Result<Vec<u8>> {
+    use std::io::Write;
+    match *encoding {
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(body)?;
+            encoder.finish()
+        }
+        Encoding::EncodingExt(ref name) if name == "br" => {
+            let mut out = Vec::new();
+            {
+                let mut writer = brotli::CompressorWriter::new(&mut out, 4096, 5, 22);
+                writer.write_all(body)?;
+            }
+            Ok(out)
+        }
+        _ => Ok(body.to_owned()),
+    }
+}
+
+/// Finish building a response body: if `mime_type` is compressible and the
+/// client advertises a supported codec, compress `body` and set
+/// `Content-Encoding`; otherwise leave it untouched. Either way sets
+/// `Content-Length` to match the body actually returned.
+fn finalize_body(
+    req: &Request,
+    mime_type: &mime::Mime,
+    headers: &mut Headers,
+    body: Vec<u8>,
+) -> Vec<u8> {
+    // As with the direct-file branch in `handle_request`, only compress
+    // bodies up to MAX_EAGER_COMPRESS_SIZE; a directory with an enormous
+    // number of entries or a very large rendered Markdown page is served
+    // uncompressed rather than gzipped in memory without bound.
+    if is_compressible(mime_type) && body.len() as u64 <= MAX_EAGER_COMPRESS_SIZE {
+        if let Some(encoding) = negotiate_encoding(req) {
+            if let Ok(compressed) = compress_body(&body, &encoding) {
+                headers.set(ContentEncoding(vec![encoding]));
+                headers.set(ContentLength(compressed.len() as u64));
+                return compressed;
+            }
+        }
+    }
+    headers.set(ContentLength(body.len() as u64));
+    body
+}
+
+/// Extensions treated as Markdown for inline rendering.
+fn is_markdown(path: &Path) -> bool {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) => ext.eq_ignore_ascii_case("md") || ext.eq_ignore_ascii_case("markdown"),
+        None => false,
+    }
+}
+
+/// Render a Markdown file to HTML and wrap it in the same Tera page shell
+/// used for directory listings.
+fn render_markdown_page(path: &Path) -> io::Result<Vec<u8>> {
+    use std::io::Read;
+    let mut source = String::new();
+    File::open(path)?.read_to_string(&mut source)?;
+
+    let mut content_html = String::new();
+    pulldown_cmark::html::push_html(&mut content_html, pulldown_cmark::Parser::new(&source));
+
+    let mut context = Context::new();
+    context.add("markdown_body", &content_html);
+    context.add("dir_name", &path.file_name().unwrap().to_str().unwrap());
+    let page = Tera::one_off(include_str!("template.html"), &context, true)
+        .unwrap_or_else(|e| format!("500 Internal server error: {}", e));
+    Ok(Vec::from(page))
+}
+
+/// Render a directory listing, either as the Tera HTML page or, when
+/// `as_json` is set, as a JSON array of `DirEntry` objects.
+fn handle_dir(dir_path: &Path, as_json: bool, options: &ServerOptions) -> io::Result<Vec<u8>> {
+    let base_path = &options.root;
+    let entries = collect_dir_entries(dir_path, base_path, options)?;
+
+    if as_json {
+        return serde_json::to_vec(&entries)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e));
+    }
+
+    // Prepare dirname of current dir relative to base path.
+    let dir_name = {
+        let base_parent = base_path.parent().unwrap_or(base_path);
+        let path = dir_path.strip_prefix(base_parent).unwrap();
+        format!("{}/", path.to_str().unwrap())
+    };
 
     // Render page with Tera template engine.
     let mut context = Context::new();
-    context.add("files", &files);
+    context.add("files", &entries);
     context.add("dir_name", &dir_name);
     let page = Tera::one_off(include_str!("template.html"), &context, true)
         .unwrap_or_else(|e| format!("500 Internal server error: {}", e));
     Ok(Vec::from(page))
 }
 
-/// Send a buffer of file to client.
-fn handle_file(file_path: &Path) -> io::Result<Vec<u8>> {
-    use std::io::prelude::*;
-    let f = File::open(file_path)?;
-    let mut buffer = Vec::new();
-    BufReader::new(f).read_to_end(&mut buffer)?;
-    Ok(buffer)
+/// Outcome of a successful `handle_file` call.
+enum FileResponse {
+    /// The entire file, streamed from start to end.
+    Full { body: hyper::Body, len: u64 },
+    /// A single byte range, streamed from `range.start` to `range.end`.
+    Partial { body: hyper::Body, range: ResolvedRange },
+}
+
+/// Failure modes specific to serving a file, as distinct from a generic I/O
+/// error that should fall back to a plain-text error body.
+enum FileError {
+    /// The request's `Range` header was syntactically valid but unsatisfiable
+    /// against the file's actual size.
+    RangeNotSatisfiable { total: u64 },
+    Io(io::Error),
+}
+
+impl From<io::Error> for FileError {
+    fn from(e: io::Error) -> Self {
+        FileError::Io(e)
+    }
+}
+
+/// Compute the caching validators for a file: a weak `ETag` derived from
+/// its size and mtime, and its `Last-Modified` date. Returns `None` if the
+/// mtime isn't available on this platform.
+fn file_validators(metadata: &std::fs::Metadata) -> Option<(EntityTag, HttpDate)> {
+    let modified = metadata.modified().ok()?;
+    let secs = modified.duration_since(std::time::UNIX_EPOCH).ok()?.as_secs();
+    let etag = EntityTag::new(true, format!("{:x}-{:x}", metadata.len(), secs));
+    Some((etag, HttpDate::from(modified)))
+}
+
+/// Whether a cached response can be served as `304 Not Modified`, per
+/// `If-None-Match` (checked first, as per RFC 7232) falling back to
+/// `If-Modified-Since`.
+fn is_cached(req_headers: &Headers, etag: &EntityTag, last_modified: HttpDate) -> bool {
+    match req_headers.get::<IfNoneMatch>() {
+        Some(&IfNoneMatch::Any) => true,
+        Some(&IfNoneMatch::Items(ref tags)) => tags.iter().any(|t| t.weak_eq(etag)),
+        None => req_headers.get::<IfModifiedSince>().map_or(false, |&IfModifiedSince(date)| {
+            last_modified <= date
+        }),
+    }
+}
+
+/// Stream a file to the client, honouring a `Range` request header if
+/// present.
+///
+/// Rather than reading the whole file into memory, the body is backed by a
+/// [`FileChunkStream`] that seeks to the requested offset and yields fixed
+/// size chunks lazily as hyper drains the body.
+fn handle_file(file_path: &Path, req_headers: &Headers) -> Result<FileResponse, FileError> {
+    let file = File::open(file_path)?;
+    let total = file.metadata()?.len();
+
+    match parse_range(req_headers, total) {
+        Ok(None) => {
+            let stream = FileChunkStream::new(file, 0, total)?;
+            Ok(FileResponse::Full { body: hyper::Body::from(Box::new(stream) as Box<
+                Stream<Item=hyper::Chunk, Error=Error> + Send
+            >), len: total })
+        }
+        Ok(Some(range)) => {
+            let stream = FileChunkStream::new(file, range.start, range.len())?;
+            Ok(FileResponse::Partial { body: hyper::Body::from(Box::new(stream) as Box<
+                Stream<Item=hyper::Chunk, Error=Error> + Send
+            >), range })
+        }
+        Err(()) => Err(FileError::RangeNotSatisfiable { total }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::{qitem, QualityItem};
+
+    fn request(uri: &str) -> Request {
+        Request::new(hyper::Method::Get, uri.parse().unwrap())
+    }
+
+    #[test]
+    fn is_compressible_matches_text_like_types() {
+        assert!(is_compressible(&mime::TEXT_HTML));
+        assert!(is_compressible(&mime::TEXT_PLAIN));
+        assert!(is_compressible(&mime::APPLICATION_JSON));
+        assert!(is_compressible(&mime::APPLICATION_JAVASCRIPT));
+        assert!(!is_compressible(&mime::IMAGE_PNG));
+    }
+
+    #[test]
+    fn negotiate_encoding_absent_header_is_none() {
+        let req = request("/");
+        assert_eq!(negotiate_encoding(&req), None);
+    }
+
+    #[test]
+    fn negotiate_encoding_picks_supported_codec() {
+        let mut req = request("/");
+        req.headers_mut().set(AcceptEncoding(vec![
+            qitem(Encoding::Gzip),
+        ]));
+        assert_eq!(negotiate_encoding(&req), Some(Encoding::Gzip));
+    }
+
+    #[test]
+    fn negotiate_encoding_ignores_unsupported_codec() {
+        let mut req = request("/");
+        req.headers_mut().set(AcceptEncoding(vec![
+            qitem(Encoding::Deflate),
+        ]));
+        assert_eq!(negotiate_encoding(&req), None);
+    }
+
+    #[test]
+    fn negotiate_encoding_prefers_higher_quality() {
+        let mut req = request("/");
+        req.headers_mut().set(AcceptEncoding(vec![
+            QualityItem::new(Encoding::Gzip, hyper::header::q(500)),
+            QualityItem::new(Encoding::EncodingExt("br".to_owned()), hyper::header::q(900)),
+        ]));
+        assert_eq!(negotiate_encoding(&req), Some(Encoding::EncodingExt("br".to_owned())));
+    }
+
+    #[test]
+    fn negotiate_encoding_rejects_explicit_q0() {
+        let mut req = request("/");
+        req.headers_mut().set(AcceptEncoding(vec![
+            QualityItem::new(Encoding::Gzip, hyper::header::q(0)),
+        ]));
+        assert_eq!(negotiate_encoding(&req), None);
+    }
+
+    #[test]
+    fn compress_body_gzip_roundtrips() {
+        use std::io::Read;
+        let compressed = compress_body(b"hello world", &Encoding::Gzip).unwrap();
+        let mut decoder = flate2::read::GzDecoder::new(&compressed[..]);
+        let mut out = String::new();
+        decoder.read_to_string(&mut out).unwrap();
+        assert_eq!(out, "hello world");
+    }
+
+    #[test]
+    fn is_cached_no_validators_is_false() {
+        let headers = Headers::new();
+        let etag = EntityTag::new(true, "abc".to_owned());
+        assert!(!is_cached(&headers, &etag, HttpDate::from(std::time::SystemTime::now())));
+    }
+
+    #[test]
+    fn is_cached_matching_if_none_match_is_true() {
+        let etag = EntityTag::new(true, "abc".to_owned());
+        let mut headers = Headers::new();
+        headers.set(IfNoneMatch::Items(vec![etag.clone()]));
+        assert!(is_cached(&headers, &etag, HttpDate::from(std::time::SystemTime::now())));
+    }
+
+    #[test]
+    fn is_cached_mismatched_if_none_match_is_false() {
+        let etag = EntityTag::new(true, "abc".to_owned());
+        let other = EntityTag::new(true, "xyz".to_owned());
+        let mut headers = Headers::new();
+        headers.set(IfNoneMatch::Items(vec![other]));
+        assert!(!is_cached(&headers, &etag, HttpDate::from(std::time::SystemTime::now())));
+    }
+
+    #[test]
+    fn is_cached_if_none_match_any_is_true() {
+        let etag = EntityTag::new(true, "abc".to_owned());
+        let mut headers = Headers::new();
+        headers.set(IfNoneMatch::Any);
+        assert!(is_cached(&headers, &etag, HttpDate::from(std::time::SystemTime::now())));
+    }
+
+    #[test]
+    fn is_cached_fresh_if_modified_since_is_true() {
+        let etag = EntityTag::new(true, "abc".to_owned());
+        let now = std::time::SystemTime::now();
+        let mut headers = Headers::new();
+        headers.set(IfModifiedSince(HttpDate::from(now)));
+        assert!(is_cached(&headers, &etag, HttpDate::from(now)));
+    }
+
+    #[test]
+    fn is_cached_stale_if_modified_since_is_false() {
+        let etag = EntityTag::new(true, "abc".to_owned());
+        let now = std::time::SystemTime::now();
+        let later = now + std::time::Duration::from_secs(60);
+        let mut headers = Headers::new();
+        headers.set(IfModifiedSince(HttpDate::from(now)));
+        assert!(!is_cached(&headers, &etag, HttpDate::from(later)));
+    }
+
+    #[test]
+    fn wants_json_via_query_param() {
+        let req = request("/?format=json");
+        assert!(wants_json(&req));
+    }
+
+    #[test]
+    fn wants_json_no_accept_header_is_false() {
+        let req = request("/");
+        assert!(!wants_json(&req));
+    }
+
+    #[test]
+    fn wants_json_explicit_json_accept_is_true() {
+        let mut req = request("/");
+        req.headers_mut().set(Accept(vec![qitem(mime::APPLICATION_JSON)]));
+        assert!(wants_json(&req));
+    }
+
+    #[test]
+    fn wants_json_q0_is_false_even_without_html() {
+        let mut req = request("/");
+        req.headers_mut().set(Accept(vec![
+            QualityItem::new(mime::APPLICATION_JSON, hyper::header::q(0)),
+        ]));
+        assert!(!wants_json(&req));
+    }
+
+    #[test]
+    fn wants_json_html_preferred_over_lower_quality_json() {
+        let mut req = request("/");
+        req.headers_mut().set(Accept(vec![
+            QualityItem::new(mime::TEXT_HTML, hyper::header::q(900)),
+            QualityItem::new(mime::APPLICATION_JSON, hyper::header::q(500)),
+        ]));
+        assert!(!wants_json(&req));
+    }
+
+    #[test]
+    fn accepts_html_no_header_is_true() {
+        let req = request("/");
+        assert!(accepts_html(&req));
+    }
+
+    #[test]
+    fn accepts_html_explicit_html_is_true() {
+        let mut req = request("/");
+        req.headers_mut().set(Accept(vec![qitem(mime::TEXT_HTML)]));
+        assert!(accepts_html(&req));
+    }
+
+    #[test]
+    fn accepts_html_wildcard_is_true() {
+        let mut req = request("/");
+        req.headers_mut().set(Accept(vec![qitem(mime::STAR_STAR)]));
+        assert!(accepts_html(&req));
+    }
+
+    #[test]
+    fn accepts_html_rejects_q0() {
+        let mut req = request("/");
+        req.headers_mut().set(Accept(vec![
+            QualityItem::new(mime::TEXT_HTML, hyper::header::q(0)),
+        ]));
+        assert!(!accepts_html(&req));
+    }
+
+    #[test]
+    fn accepts_html_only_json_is_false() {
+        let mut req = request("/");
+        req.headers_mut().set(Accept(vec![qitem(mime::APPLICATION_JSON)]));
+        assert!(!accepts_html(&req));
+    }
+
+    #[test]
+    fn wants_raw_absent_is_false() {
+        let req = request("/README.md");
+        assert!(!wants_raw(&req));
+    }
+
+    #[test]
+    fn wants_raw_query_param_is_true() {
+        let req = request("/README.md?raw=1");
+        assert!(wants_raw(&req));
+    }
+
+    #[test]
+    fn parse_range_absent_is_none() {
+        let headers = Headers::new();
+        assert_eq!(parse_range(&headers, 100).unwrap(), None);
+    }
+
+    #[test]
+    fn parse_range_from_to_is_satisfiable() {
+        let mut headers = Headers::new();
+        headers.set(Range::bytes(0, 9));
+        let range = parse_range(&headers, 100).unwrap().unwrap();
+        assert_eq!((range.start, range.end, range.total), (0, 9, 100));
+        assert_eq!(range.len(), 10);
+    }
+
+    #[test]
+    fn parse_range_all_from_clamps_to_end() {
+        let mut headers = Headers::new();
+        headers.set(Range::Bytes(vec![ByteRangeSpec::AllFrom(90)]));
+        let range = parse_range(&headers, 100).unwrap().unwrap();
+        assert_eq!((range.start, range.end), (90, 99));
+    }
+
+    #[test]
+    fn parse_range_suffix_is_last_n_bytes() {
+        let mut headers = Headers::new();
+        headers.set(Range::Bytes(vec![ByteRangeSpec::Last(10)]));
+        let range = parse_range(&headers, 100).unwrap().unwrap();
+        assert_eq!((range.start, range.end), (90, 99));
+    }
+
+    #[test]
+    fn parse_range_out_of_bounds_is_err() {
+        let mut headers = Headers::new();
+        headers.set(Range::bytes(50, 150));
+        assert!(parse_range(&headers, 100).is_err());
+    }
+
+    #[test]
+    fn parse_range_zero_length_suffix_is_err() {
+        let mut headers = Headers::new();
+        headers.set(Range::Bytes(vec![ByteRangeSpec::Last(0)]));
+        assert!(parse_range(&headers, 100).is_err());
+    }
+
+    /// A scratch directory under the OS temp dir, removed on drop, used to
+    /// exercise `resolve_path` against a real (canonical) filesystem root.
+    struct TempDir {
+        path: PathBuf,
+    }
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir()
+                .join(format!("sfz-test-{}-{}-{:?}", name, std::process::id(), std::time::SystemTime::now()));
+            std::fs::create_dir_all(&path).unwrap();
+            TempDir { path }
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.path);
+        }
+    }
+
+    #[test]
+    fn resolve_path_serves_a_file_under_root() {
+        let dir = TempDir::new("ok");
+        std::fs::write(dir.path.join("index.html"), b"hi").unwrap();
+        let root = dir.path.canonicalize().unwrap();
+
+        let resolved = resolve_path(&root, "index.html").unwrap();
+        assert_eq!(resolved, root.join("index.html"));
+    }
+
+    #[test]
+    fn resolve_path_rejects_dotdot_traversal() {
+        let dir = TempDir::new("traversal");
+        let root = dir.path.canonicalize().unwrap();
+
+        let err = resolve_path(&root, "../etc/passwd").unwrap_err();
+        assert_eq!(err, PathResolutionError::Forbidden);
+    }
+
+    #[test]
+    fn resolve_path_reports_missing_files_as_not_found() {
+        let dir = TempDir::new("missing");
+        let root = dir.path.canonicalize().unwrap();
+
+        let err = resolve_path(&root, "does-not-exist").unwrap_err();
+        assert_eq!(err, PathResolutionError::NotFound);
+    }
+
+    #[test]
+    fn resolve_path_rejects_symlink_escape() {
+        let dir = TempDir::new("symlink");
+        let root = dir.path.join("public");
+        std::fs::create_dir_all(&root).unwrap();
+        let root = root.canonicalize().unwrap();
+
+        let secret = dir.path.join("secret.txt");
+        std::fs::write(&secret, b"top secret").unwrap();
+
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(&secret, root.join("escape")).unwrap();
+
+        #[cfg(unix)]
+        {
+            let err = resolve_path(&root, "escape").unwrap_err();
+            assert_eq!(err, PathResolutionError::Forbidden);
+        }
+    }
+
+    #[test]
+    fn resolve_path_accepts_a_relative_root() {
+        let dir = TempDir::new("relative-root");
+        std::fs::write(dir.path.join("a.txt"), b"a").unwrap();
+        // Exercise the exact `--root .`-style scenario from the bug report:
+        // a root that isn't already in canonical form.
+        let relative_root = dir.path.join(".").join("..").join(
+            dir.path.file_name().unwrap(),
+        );
+        let canonical_root = relative_root.canonicalize().unwrap();
+        let server = MyServer::new(ServerOptions {
+            root: relative_root,
+            ..ServerOptions::default()
+        });
+
+        let resolved = resolve_path(&server.options.root, "a.txt").unwrap();
+        assert_eq!(resolved, canonical_root.join("a.txt"));
+    }
 }